@@ -1,7 +1,10 @@
 use scylla::SessionBuilder;
 use scylla::Session;
-use scylla::IntoTypedRows;
+use scylla::prepared_statement::PreparedStatement;
 use scylla::query::Query;
+use scylla::frame::value::SerializedValues;
+use futures::{stream, Stream, StreamExt};
+use quickcheck::{Arbitrary, Gen};
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::fs::File;
@@ -33,29 +36,158 @@ pub fn get_interval(key_prefix: Vec<u8>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
     (Bound::Included(key_prefix), upper_bound)
 }
 
-async fn find_key_values_by_prefix(session: &Session, key_prefix: Vec<u8>) -> Vec<(Vec<u8>,Vec<u8>)> {
-    let len = key_prefix.len();
-    let rows = match get_upper_bound_option(&key_prefix) {
+/// Length, in bytes, of the key prefix used as the partition key (`bucket`
+/// column). Keeping it short and fixed bounds how many buckets a short
+/// `DeletePrefix`/scan has to fan out across.
+pub const BUCKET_PREFIX_LEN: usize = 1;
+
+/// Splits a full key into its partition bucket (the first `BUCKET_PREFIX_LEN`
+/// bytes, or the whole key if it's shorter) and the clustering suffix stored
+/// in the `k` column.
+fn split_key(key: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let split_at = key.len().min(BUCKET_PREFIX_LEN);
+    (key[..split_at].to_vec(), key[split_at..].to_vec())
+}
+
+/// Enumerates the buckets a `key_prefix` can fall under. When the prefix is
+/// at least `BUCKET_PREFIX_LEN` bytes it pins down exactly one bucket; when
+/// it's shorter, every completion of the missing bytes is a candidate
+/// bucket, so the scan/delete has to fan out across all of them.
+fn buckets_for_prefix(key_prefix: &[u8]) -> Vec<Vec<u8>> {
+    if key_prefix.len() >= BUCKET_PREFIX_LEN {
+        return vec![key_prefix[..BUCKET_PREFIX_LEN].to_vec()];
+    }
+    let mut buckets = vec![key_prefix.to_vec()];
+    for _ in key_prefix.len()..BUCKET_PREFIX_LEN {
+        let mut next = Vec::with_capacity(buckets.len() * 256);
+        for bucket in &buckets {
+            for b in 0u8..=255u8 {
+                let mut candidate = bucket.clone();
+                candidate.push(b);
+                next.push(candidate);
+            }
+        }
+        buckets = next;
+    }
+    buckets
+}
+
+/// For each bucket a `key_prefix` can fall under, the lower bound to apply
+/// to that bucket's `k` column. When the prefix reaches into a bucket (i.e.
+/// it's at least `BUCKET_PREFIX_LEN` bytes), the remaining bytes become a
+/// clustering lower bound; when the whole bucket is targeted, the bound is
+/// empty (matches every row in the bucket).
+fn bucket_targets_for_prefix(key_prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    if key_prefix.len() >= BUCKET_PREFIX_LEN {
+        let (bucket, suffix) = key_prefix.split_at(BUCKET_PREFIX_LEN);
+        vec![(bucket.to_vec(), suffix.to_vec())]
+    } else {
+        buckets_for_prefix(key_prefix)
+            .into_iter()
+            .map(|bucket| (bucket, Vec::new()))
+            .collect()
+    }
+}
+
+/// Page size used by `scan_prefix`'s server-side paging, chosen to keep
+/// memory flat for large key ranges while still amortizing round-trips.
+const SCAN_PAGE_SIZE: i32 = 1000;
+
+/// Reassembles consecutive rows that share the same `k` into a single
+/// value, concatenating chunks in clustering (ascending `chunk_idx`) order.
+/// Relies on the caller's query returning rows ordered by clustering key,
+/// which a partition-scoped range scan does by default. The `k` column is
+/// returned as-is (it's only the clustering suffix within a bucket); the
+/// caller is responsible for turning it back into a full key.
+fn reassemble_chunks<S>(rows: S) -> impl Stream<Item = (Vec<u8>, Vec<u8>)>
+where
+    S: Stream<Item = (Vec<u8>, i32, Vec<u8>, i32)>,
+{
+    stream::unfold(
+        (Box::pin(rows), None::<(Vec<u8>, Vec<Vec<u8>>, i32)>),
+        move |(mut rows, mut pending)| async move {
+            loop {
+                match rows.next().await {
+                    Some((key, _chunk_idx, chunk, n_chunks)) => match pending.take() {
+                        Some((pending_key, mut chunks, pending_n_chunks)) if pending_key == key => {
+                            chunks.push(chunk);
+                            pending = Some((pending_key, chunks, pending_n_chunks));
+                        }
+                        Some((done_key, done_chunks, done_n_chunks)) => {
+                            debug_assert_eq!(done_chunks.len() as i32, done_n_chunks);
+                            pending = Some((key, vec![chunk], n_chunks));
+                            let value = done_chunks.concat();
+                            return Some(((done_key, value), (rows, pending)));
+                        }
+                        None => {
+                            pending = Some((key, vec![chunk], n_chunks));
+                        }
+                    },
+                    None => {
+                        return pending.take().map(|(done_key, done_chunks, done_n_chunks)| {
+                            debug_assert_eq!(done_chunks.len() as i32, done_n_chunks);
+                            let value = done_chunks.concat();
+                            ((done_key, value), (rows, None))
+                        });
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Streams the key/value pairs of a single bucket whose clustering key starts
+/// with `suffix_lower` (every row, when it is empty), reassembling chunked
+/// values. Returned keys are full
+/// keys (`bucket` with `k` appended back on), not yet stripped of any
+/// prefix — that's `scan_prefix`'s job once all buckets have been merged.
+async fn scan_bucket(
+    session: &Session,
+    bucket: Vec<u8>,
+    suffix_lower: Vec<u8>,
+) -> impl Stream<Item = (Vec<u8>, Vec<u8>)> + '_ {
+    let row_iterator = match get_upper_bound_option(&suffix_lower) {
         None => {
-            let values = (key_prefix,);
-            let query = "SELECT k,v FROM kv.pairs WHERE dummy = 0 AND k >= ? ALLOW FILTERING";
-            session.query(query, values).await.unwrap()
+            let mut query = Query::new("SELECT k,chunk_idx,v,n_chunks FROM kv.pairs WHERE bucket = ? AND k >= ?");
+            query.set_page_size(SCAN_PAGE_SIZE);
+            let values = (bucket.clone(), suffix_lower);
+            session.query_iter(query, values).await.unwrap()
         }
         Some(upper_bound) => {
-            let values = (key_prefix, upper_bound);
-            let query = "SELECT k,v FROM kv.pairs WHERE dummy = 0 AND k >= ? AND k < ? ALLOW FILTERING";
-            session.query(query, values).await.unwrap()
+            let mut query =
+                Query::new("SELECT k,chunk_idx,v,n_chunks FROM kv.pairs WHERE bucket = ? AND k >= ? AND k < ?");
+            query.set_page_size(SCAN_PAGE_SIZE);
+            let values = (bucket.clone(), suffix_lower, upper_bound);
+            session.query_iter(query, values).await.unwrap()
         }
     };
-    let mut key_values = Vec::new();
-    if let Some(rows) = rows.rows {
-        for row in rows.into_typed::<(Vec<u8>,Vec<u8>)>() {
-            let key = row.unwrap();
-            let short_key = key.0[len..].to_vec();
-            key_values.push((short_key, key.1));
-        }
-    }
-    key_values
+    let rows = row_iterator
+        .into_typed::<(Vec<u8>, i32, Vec<u8>, i32)>()
+        .map(|row| row.unwrap());
+    reassemble_chunks(rows).map(move |(suffix, value)| {
+        let mut full_key = bucket.clone();
+        full_key.extend(suffix);
+        (full_key, value)
+    })
+}
+
+/// Streams the key/value pairs under `key_prefix`, fanning out across every
+/// bucket the prefix can touch (in ascending bucket order, so the merged
+/// output stays in key order without an explicit k-way merge) and using the
+/// driver's paged `query_iter` within each bucket so a wide prefix never has
+/// to be materialized into memory all at once. Returned keys have
+/// `key_prefix` stripped, matching the short-key convention the rest of this
+/// module relies on.
+async fn scan_prefix(
+    session: &Session,
+    key_prefix: Vec<u8>,
+) -> impl Stream<Item = (Vec<u8>, Vec<u8>)> + '_ {
+    let len = key_prefix.len();
+    let targets = bucket_targets_for_prefix(&key_prefix);
+    stream::iter(targets)
+        .then(move |(bucket, suffix_lower)| scan_bucket(session, bucket, suffix_lower))
+        .flatten()
+        .map(move |(full_key, value)| (full_key[len..].to_vec(), value))
 }
 
 #[derive(Clone, Debug)]
@@ -70,6 +202,22 @@ pub enum WriteOperation {
         key: Vec<u8>,
         value: Vec<u8>,
     },
+    /// Inserts `key`/`value` only if `key` is currently absent, compiling to
+    /// `INSERT ... IF NOT EXISTS`.
+    PutIfAbsent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    /// Applies `new` (or deletes, if `None`) only if the current value at
+    /// `key` equals `expected` (absent, if `None`), compiling to
+    /// `UPDATE ... IF v = ?` / `DELETE ... IF v = ?`; with `expected: None`
+    /// it compiles to `INSERT ... IF NOT EXISTS`, or to a plain existence
+    /// read when `new` is also `None`.
+    CompareAndSet {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -77,6 +225,127 @@ pub struct Batch {
     pub operations: Vec<WriteOperation>,
 }
 
+/// Logged batches go through the batchlog for atomicity across partitions,
+/// at extra coordinator cost. Callers that don't need that guarantee (e.g.
+/// a sub-batch that `split_batch` already confined to a single partition)
+/// can opt into `Unlogged` to skip it.
+#[derive(Clone, Copy, Debug)]
+pub enum WriteBatchType {
+    Logged,
+    Unlogged,
+}
+
+impl From<WriteBatchType> for scylla::frame::request::batch::BatchType {
+    fn from(batch_type: WriteBatchType) -> Self {
+        match batch_type {
+            WriteBatchType::Logged => scylla::frame::request::batch::BatchType::Logged,
+            WriteBatchType::Unlogged => scylla::frame::request::batch::BatchType::Unlogged,
+        }
+    }
+}
+
+/// ScyllaDB warns past ~128 KiB of serialized mutations in a single batch
+/// and rejects batches that are too large; this is the default threshold
+/// `split_batch` accumulates operations against.
+pub const DEFAULT_MAX_BATCH_BYTES: usize = 128 * 1024;
+
+/// Rough fixed overhead (cell/frame metadata) added on top of the raw key
+/// and value bytes when estimating how much room an operation takes in a
+/// serialized batch.
+const OPERATION_FIXED_OVERHEAD: usize = 32;
+
+fn estimate_operation_size(operation: &WriteOperation) -> usize {
+    match operation {
+        WriteOperation::Put { key, value } => OPERATION_FIXED_OVERHEAD + key.len() + value.len(),
+        WriteOperation::Delete { key } => OPERATION_FIXED_OVERHEAD + key.len(),
+        WriteOperation::DeletePrefix { key_prefix } => OPERATION_FIXED_OVERHEAD + key_prefix.len(),
+        WriteOperation::PutIfAbsent { key, value } => OPERATION_FIXED_OVERHEAD + key.len() + value.len(),
+        WriteOperation::CompareAndSet { key, expected, new } => {
+            OPERATION_FIXED_OVERHEAD
+                + key.len()
+                + expected.as_ref().map_or(0, Vec::len)
+                + new.as_ref().map_or(0, Vec::len)
+        }
+    }
+}
+
+/// The partition (`bucket` column) an operation falls into. Callers must run
+/// `expand_delete_prefix` first so every `DeletePrefix` reaching this point
+/// already targets a single bucket.
+fn partition_key(operation: &WriteOperation) -> Vec<u8> {
+    match operation {
+        WriteOperation::Put { key, .. }
+        | WriteOperation::Delete { key }
+        | WriteOperation::PutIfAbsent { key, .. }
+        | WriteOperation::CompareAndSet { key, .. } => split_key(key).0,
+        WriteOperation::DeletePrefix { key_prefix } => {
+            debug_assert!(
+                key_prefix.len() >= BUCKET_PREFIX_LEN,
+                "expand_delete_prefix must run before partition_key sees a DeletePrefix"
+            );
+            key_prefix[..BUCKET_PREFIX_LEN.min(key_prefix.len())].to_vec()
+        }
+    }
+}
+
+/// Expands a `DeletePrefix` whose prefix is shorter than `BUCKET_PREFIX_LEN`
+/// into one `DeletePrefix` per bucket it spans, before the operation ever
+/// reaches `split_batch`/`send_sub_batch`. Each resulting delete is then a
+/// single-partition operation that `estimate_operation_size` and
+/// `partition_key` account for like any other, and `send_sub_batch` only
+/// ever sees a `DeletePrefix` that targets one bucket.
+fn expand_delete_prefix(operations: Vec<WriteOperation>) -> Vec<WriteOperation> {
+    operations
+        .into_iter()
+        .flat_map(|operation| match operation {
+            WriteOperation::DeletePrefix { key_prefix } if key_prefix.len() < BUCKET_PREFIX_LEN => {
+                buckets_for_prefix(&key_prefix)
+                    .into_iter()
+                    .map(|bucket| WriteOperation::DeletePrefix { key_prefix: bucket })
+                    .collect::<Vec<_>>()
+            }
+            other => vec![other],
+        })
+        .collect()
+}
+
+/// Splits `operations` into sub-batches that (a) stay under `max_bytes` of
+/// estimated serialized size and (b) group operations touching the same
+/// partition together, so each emitted sub-batch is single-partition where
+/// possible. Writes to distinct keys commute, so grouping by partition
+/// across the whole input (not just adjacent operations) is safe; relative
+/// order is still preserved within each partition's group and each group's
+/// sub-batches.
+fn split_batch(operations: Vec<WriteOperation>, max_bytes: usize) -> Vec<Vec<WriteOperation>> {
+    let mut partitions: Vec<(Vec<u8>, Vec<WriteOperation>)> = Vec::new();
+    for operation in operations {
+        let key = partition_key(&operation);
+        match partitions.iter_mut().find(|(existing_key, _)| *existing_key == key) {
+            Some((_, ops)) => ops.push(operation),
+            None => partitions.push((key, vec![operation])),
+        }
+    }
+
+    let mut sub_batches = Vec::new();
+    for (_key, ops) in partitions {
+        let mut current = Vec::new();
+        let mut current_size = 0;
+        for operation in ops {
+            let size = estimate_operation_size(&operation);
+            if !current.is_empty() && current_size + size > max_bytes {
+                sub_batches.push(std::mem::take(&mut current));
+                current_size = 0;
+            }
+            current_size += size;
+            current.push(operation);
+        }
+        if !current.is_empty() {
+            sub_batches.push(current);
+        }
+    }
+    sub_batches
+}
+
 fn print_batch(batch: &Batch) {
     println!("batch, n_operation={}", batch.operations.len());
     let mut pos = 0;
@@ -91,6 +360,12 @@ fn print_batch(batch: &Batch) {
             WriteOperation::DeletePrefix { key_prefix } => {
                 println!("{}: DeletePrefix key_prefix={:?}", pos, key_prefix);
             }
+            WriteOperation::PutIfAbsent { key, value } => {
+                println!("{}: PutIfAbsent key={:?} value={:?}", pos, key, value);
+            }
+            WriteOperation::CompareAndSet { key, expected, new } => {
+                println!("{}: CompareAndSet key={:?} expected={:?} new={:?}", pos, key, expected, new);
+            }
         }
         pos += 1;
     }
@@ -111,6 +386,12 @@ fn detect_collision(batch: &Batch) {
             WriteOperation::DeletePrefix { key_prefix } => {
                 key_prefix_deletes.insert(key_prefix.clone());
             }
+            WriteOperation::PutIfAbsent { key, value: _ } => {
+                key_puts.insert(key.clone());
+            }
+            WriteOperation::CompareAndSet { key, expected: _, new: _ } => {
+                key_puts.insert(key.clone());
+            }
         }
     }
     println!("|key_puts|={}", key_puts.len());
@@ -129,46 +410,332 @@ fn detect_collision(batch: &Batch) {
 
 
 
-async fn write_batch_internal(
+/// Prepared statements for every CQL shape used by `write_batch_internal_with`,
+/// built once at session creation so that batches never re-parse raw CQL
+/// strings and the driver can perform token-aware routing.
+pub struct PreparedStatements {
+    insert: PreparedStatement,
+    delete: PreparedStatement,
+    delete_prefix_bounded: PreparedStatement,
+    delete_prefix_unbounded: PreparedStatement,
+    lwt_insert_if_not_exists: PreparedStatement,
+    lwt_update_if_eq: PreparedStatement,
+    lwt_delete_if_eq: PreparedStatement,
+    delete_trailing_chunks: PreparedStatement,
+    select_exists: PreparedStatement,
+}
+
+impl PreparedStatements {
+    async fn new(session: &Session) -> PreparedStatements {
+        let insert = session
+            .prepare("INSERT INTO kv.pairs (bucket, k, chunk_idx, v, n_chunks) VALUES (?, ?, ?, ?, ?)")
+            .await
+            .unwrap();
+        let delete = session
+            .prepare("DELETE FROM kv.pairs WHERE bucket = ? AND k = ?")
+            .await
+            .unwrap();
+        let delete_prefix_bounded = session
+            .prepare("DELETE FROM kv.pairs WHERE bucket = ? AND k >= ? AND k < ?")
+            .await
+            .unwrap();
+        let delete_prefix_unbounded = session
+            .prepare("DELETE FROM kv.pairs WHERE bucket = ? AND k >= ?")
+            .await
+            .unwrap();
+        // LWTs address a single, unchunked clustering row (`chunk_idx = 0`).
+        // The `n_chunks = 1` guard on the update/delete conditions keeps a
+        // CAS from ever matching against just the first fragment of a value
+        // that a plain `Put` had split across multiple chunk rows; on a
+        // mismatch it falls through to `applied = false` instead of
+        // comparing `expected` against a truncated value. `delete_trailing_chunks`
+        // then sweeps up any `chunk_idx >= 1` rows a successful conditional
+        // write leaves behind, so a later `scan_prefix` never reassembles a
+        // fresh single-chunk value with leftover fragments from before.
+        let lwt_insert_if_not_exists = session
+            .prepare(
+                "INSERT INTO kv.pairs (bucket, k, chunk_idx, v, n_chunks) VALUES (?, ?, 0, ?, 1) IF NOT EXISTS",
+            )
+            .await
+            .unwrap();
+        let lwt_update_if_eq = session
+            .prepare(
+                "UPDATE kv.pairs SET v = ?, n_chunks = 1 WHERE bucket = ? AND k = ? AND chunk_idx = 0 IF v = ? AND n_chunks = 1",
+            )
+            .await
+            .unwrap();
+        let lwt_delete_if_eq = session
+            .prepare(
+                "DELETE FROM kv.pairs WHERE bucket = ? AND k = ? AND chunk_idx = 0 IF v = ? AND n_chunks = 1",
+            )
+            .await
+            .unwrap();
+        let delete_trailing_chunks = session
+            .prepare("DELETE FROM kv.pairs WHERE bucket = ? AND k = ? AND chunk_idx > 0")
+            .await
+            .unwrap();
+        // Plain (non-LWT) existence check backing `CompareAndSet { expected:
+        // None, new: None }`, which asserts absence without writing anything
+        // and so has no mutating LWT to piggyback the check on.
+        let select_exists = session
+            .prepare("SELECT k FROM kv.pairs WHERE bucket = ? AND k = ? AND chunk_idx = 0")
+            .await
+            .unwrap();
+        PreparedStatements {
+            insert,
+            delete,
+            delete_prefix_bounded,
+            delete_prefix_unbounded,
+            lwt_insert_if_not_exists,
+            lwt_update_if_eq,
+            lwt_delete_if_eq,
+            delete_trailing_chunks,
+            select_exists,
+        }
+    }
+}
+
+/// Extracts the driver's `[applied]` column from a lightweight-transaction
+/// response. LWT responses put `[applied]` first regardless of whether the
+/// condition held, so this ignores any trailing "current value" columns
+/// the driver includes on a failed condition.
+fn applied_from_result(result: scylla::QueryResult) -> bool {
+    let row = result
+        .rows
+        .and_then(|rows| rows.into_iter().next())
+        .expect("LWT response row");
+    match row.columns.first() {
+        Some(Some(scylla::frame::response::result::CqlValue::Boolean(applied))) => *applied,
+        _ => panic!("Unexpected LWT response shape"),
+    }
+}
+
+/// Removes any `chunk_idx >= 1` rows left behind by a value that a plain
+/// `Put` had chunked before a conditional write replaced or deleted its
+/// `chunk_idx = 0` row, so a later `scan_prefix` doesn't reassemble a fresh
+/// value with stale trailing fragments.
+async fn cleanup_trailing_chunks(session: &Session, prepared: &PreparedStatements, bucket: &Vec<u8>, k: &Vec<u8>) {
+    session
+        .execute(&prepared.delete_trailing_chunks, (bucket, k))
+        .await
+        .unwrap();
+}
+
+/// Whether `key` currently has a row, via a plain (non-LWT) read.
+async fn key_exists(session: &Session, prepared: &PreparedStatements, key: &[u8]) -> bool {
+    let (bucket, k) = split_key(key);
+    let result = session.execute(&prepared.select_exists, (bucket, k)).await.unwrap();
+    result.rows.map_or(false, |rows| !rows.is_empty())
+}
+
+/// Executes a `PutIfAbsent`/`CompareAndSet` operation as a standalone LWT,
+/// since lightweight transactions cannot be mixed into an ordinary batch.
+async fn execute_conditional(
     session: &Session,
+    prepared: &PreparedStatements,
+    operation: &WriteOperation,
+) -> bool {
+    match operation {
+        WriteOperation::PutIfAbsent { key, value } => {
+            let (bucket, k) = split_key(key);
+            let result = session
+                .execute(&prepared.lwt_insert_if_not_exists, (&bucket, &k, value))
+                .await
+                .unwrap();
+            let applied = applied_from_result(result);
+            if applied {
+                cleanup_trailing_chunks(session, prepared, &bucket, &k).await;
+            }
+            applied
+        }
+        WriteOperation::CompareAndSet { key, expected, new } => match (expected, new) {
+            (Some(expected_value), Some(new_value)) => {
+                let (bucket, k) = split_key(key);
+                let result = session
+                    .execute(&prepared.lwt_update_if_eq, (new_value, &bucket, &k, expected_value))
+                    .await
+                    .unwrap();
+                let applied = applied_from_result(result);
+                if applied {
+                    cleanup_trailing_chunks(session, prepared, &bucket, &k).await;
+                }
+                applied
+            }
+            (Some(expected_value), None) => {
+                let (bucket, k) = split_key(key);
+                let result = session
+                    .execute(&prepared.lwt_delete_if_eq, (&bucket, &k, expected_value))
+                    .await
+                    .unwrap();
+                let applied = applied_from_result(result);
+                if applied {
+                    cleanup_trailing_chunks(session, prepared, &bucket, &k).await;
+                }
+                applied
+            }
+            (None, Some(new_value)) => {
+                let (bucket, k) = split_key(key);
+                let result = session
+                    .execute(&prepared.lwt_insert_if_not_exists, (&bucket, &k, new_value))
+                    .await
+                    .unwrap();
+                let applied = applied_from_result(result);
+                if applied {
+                    cleanup_trailing_chunks(session, prepared, &bucket, &k).await;
+                }
+                applied
+            }
+            // Writes nothing either way, but still reports whether the key
+            // was actually absent, as the condition claims.
+            (None, None) => !key_exists(session, prepared, key).await,
+        },
+        _ => unreachable!("execute_conditional called on an unconditional operation"),
+    }
+}
+
+/// ScyllaDB has a practical per-cell/frame size limit, so a value larger
+/// than this is split into ordered chunks stored as separate clustering
+/// rows (`chunk_idx` 0..n) rather than written whole into a single cell.
+pub const DEFAULT_MAX_CHUNK_BYTES: usize = 1024 * 1024;
+
+/// Splits `value` into ordered, non-overlapping chunks of at most
+/// `max_chunk_bytes`. An empty value still yields a single empty chunk, so
+/// every key has at least one chunk row.
+fn chunk_value(value: Vec<u8>, max_chunk_bytes: usize) -> Vec<Vec<u8>> {
+    if value.is_empty() {
+        return vec![Vec::new()];
+    }
+    value.chunks(max_chunk_bytes).map(|chunk| chunk.to_vec()).collect()
+}
+
+async fn send_sub_batch(
+    session: &Session,
+    prepared: &PreparedStatements,
+    batch_type: WriteBatchType,
     operations: Vec<WriteOperation>,
+    max_chunk_bytes: usize,
 ) {
-    let mut batch_query = scylla::statement::batch::Batch::new(scylla::frame::request::batch::BatchType::Logged);
+    let mut batch_query = scylla::statement::batch::Batch::new(batch_type.into());
     let mut batch_values = Vec::new();
     for ent in operations {
-        let (query, values) = match ent {
+        match ent {
             WriteOperation::Put { key, value } => {
-                let query = "INSERT INTO kv.pairs (dummy, k, v) VALUES (0, ?, ?)";
-                let values = vec![key, value];
-                (query, values)
+                let (bucket, k) = split_key(&key);
+                let chunks = chunk_value(value, max_chunk_bytes);
+                let n_chunks = chunks.len() as i32;
+                for (chunk_idx, chunk) in chunks.into_iter().enumerate() {
+                    let mut values = SerializedValues::new();
+                    values.add_value(&bucket).unwrap();
+                    values.add_value(&k).unwrap();
+                    values.add_value(&(chunk_idx as i32)).unwrap();
+                    values.add_value(&chunk).unwrap();
+                    values.add_value(&n_chunks).unwrap();
+                    batch_values.push(values);
+                    batch_query.append_statement(prepared.insert.clone());
+                }
             }
             WriteOperation::Delete { key } => {
-                let query = "DELETE FROM kv.pairs WHERE dummy = 0 AND k = ?";
-                let values = vec![key];
-                (query, values)
+                let (bucket, k) = split_key(&key);
+                let mut values = SerializedValues::new();
+                values.add_value(&bucket).unwrap();
+                values.add_value(&k).unwrap();
+                batch_values.push(values);
+                batch_query.append_statement(prepared.delete.clone());
             }
             WriteOperation::DeletePrefix { key_prefix } => {
-                match get_upper_bound_option(&key_prefix) {
+                // `expand_delete_prefix` has already fanned this out to a
+                // single bucket by the time it reaches here.
+                let (bucket, suffix_lower) = split_key(&key_prefix);
+                match get_upper_bound_option(&suffix_lower) {
                     None => {
-                        let values = vec![key_prefix];
-                        let query = "DELETE FROM kv.pairs WHERE dummy = 0 AND k >= ?";
-                        (query, values)
+                        let mut values = SerializedValues::new();
+                        values.add_value(&bucket).unwrap();
+                        values.add_value(&suffix_lower).unwrap();
+                        batch_values.push(values);
+                        batch_query.append_statement(prepared.delete_prefix_unbounded.clone());
                     }
                     Some(upper_bound) => {
-                        let values = vec![key_prefix, upper_bound];
-                        let query = "DELETE FROM kv.pairs WHERE dummy = 0 AND k >= ? AND k < ?";
-                        (query, values)
+                        let mut values = SerializedValues::new();
+                        values.add_value(&bucket).unwrap();
+                        values.add_value(&suffix_lower).unwrap();
+                        values.add_value(&upper_bound).unwrap();
+                        batch_values.push(values);
+                        batch_query.append_statement(prepared.delete_prefix_bounded.clone());
                     }
                 }
             }
+            WriteOperation::PutIfAbsent { .. } | WriteOperation::CompareAndSet { .. } => {
+                unreachable!("conditional operations are filtered out before send_sub_batch")
+            }
         };
-        batch_values.push(values);
-        let query = Query::new(query);
-        batch_query.append_statement(query);
     }
     session.batch(&batch_query, batch_values).await.unwrap();
 }
 
+/// Sends and clears whatever unconditional ops have accumulated in `pending`,
+/// so a caller that's about to run a conditional op can flush the run that
+/// precedes it in the caller's order first.
+async fn flush_unconditional(
+    session: &Session,
+    prepared: &PreparedStatements,
+    batch_type: WriteBatchType,
+    pending: &mut Vec<WriteOperation>,
+    max_batch_bytes: usize,
+    max_chunk_bytes: usize,
+) {
+    if pending.is_empty() {
+        return;
+    }
+    let operations = expand_delete_prefix(std::mem::take(pending));
+    for sub_batch in split_batch(operations, max_batch_bytes) {
+        send_sub_batch(session, prepared, batch_type, sub_batch, max_chunk_bytes).await;
+    }
+}
+
+/// Runs `operations` against Scylla and returns, in the caller's order,
+/// whether each operation was applied. Unconditional operations (`Put`,
+/// `Delete`, `DeletePrefix`) always apply and go through `split_batch`,
+/// flushed as separate sub-batches so they are not packed into one
+/// oversized or needlessly cross-partition logged batch (a single operation
+/// larger than `max_batch_bytes` still goes out alone in its own sub-batch);
+/// values longer than `max_chunk_bytes` are further split across clustering
+/// rows. `PutIfAbsent`/`CompareAndSet` are LWTs and are executed individually,
+/// since lightweight transactions cannot be mixed into an ordinary batch.
+async fn write_batch_internal_with(
+    session: &Session,
+    prepared: &PreparedStatements,
+    operations: Vec<WriteOperation>,
+    batch_type: WriteBatchType,
+    max_batch_bytes: usize,
+    max_chunk_bytes: usize,
+) -> Vec<bool> {
+    let mut applied = vec![true; operations.len()];
+    let mut unconditional = Vec::new();
+    for (idx, operation) in operations.into_iter().enumerate() {
+        match &operation {
+            WriteOperation::PutIfAbsent { .. } | WriteOperation::CompareAndSet { .. } => {
+                // Flush the unconditional run collected so far first, so an
+                // earlier `Put` on the same key actually lands in Scylla
+                // before this conditional op reads/compares against it —
+                // matching the order the oracle applies operations in.
+                flush_unconditional(
+                    session,
+                    prepared,
+                    batch_type,
+                    &mut unconditional,
+                    max_batch_bytes,
+                    max_chunk_bytes,
+                )
+                .await;
+                applied[idx] = execute_conditional(session, prepared, &operation).await;
+            }
+            _ => unconditional.push(operation),
+        }
+    }
+    flush_unconditional(session, prepared, batch_type, &mut unconditional, max_batch_bytes, max_chunk_bytes).await;
+    applied
+}
+
 async fn create_test_session() -> Session {
     // Create a session builder and specify the ScyllaDB contact points
     let session_builder = SessionBuilder::new()
@@ -193,35 +760,54 @@ async fn create_test_session() -> Session {
     // Create a table if it doesn't exist
     session
         .query(
-            "CREATE TABLE IF NOT EXISTS kv.pairs (dummy int, k blob, v blob, primary key (dummy, k))",
+            "CREATE TABLE IF NOT EXISTS kv.pairs (bucket blob, k blob, chunk_idx int, v blob, n_chunks int, primary key (bucket, k, chunk_idx))",
             &[],
         )
         .await.unwrap();
     session
 }
 
-fn update_via_batch(kv_state: &mut BTreeMap<Vec<u8>,Vec<u8>>, batch: &Batch) {
-    for operation in &batch.operations {
-        match operation {
-            WriteOperation::Put { key, value } => {
-                kv_state.insert(key.to_vec(), value.to_vec());
-            }
-            WriteOperation::Delete { key } => {
-                kv_state.remove(key);
+fn apply_operation(kv_state: &mut BTreeMap<Vec<u8>, Vec<u8>>, operation: &WriteOperation) {
+    match operation {
+        WriteOperation::Put { key, value } => {
+            kv_state.insert(key.to_vec(), value.to_vec());
+        }
+        WriteOperation::Delete { key } => {
+            kv_state.remove(key);
+        }
+        WriteOperation::DeletePrefix { key_prefix } => {
+            let key_list = kv_state
+                .range(get_interval(key_prefix.clone()))
+                .map(|x| x.0.to_vec())
+                .collect::<Vec<_>>();
+            for key in key_list {
+                kv_state.remove(&key);
             }
-            WriteOperation::DeletePrefix { key_prefix } => {
-                let key_list = kv_state
-                    .range(get_interval(key_prefix.clone()))
-                    .map(|x| x.0.to_vec())
-                    .collect::<Vec<_>>();
-                for key in key_list {
-                    kv_state.remove(&key);
+        }
+        WriteOperation::PutIfAbsent { key, value } => {
+            kv_state.entry(key.to_vec()).or_insert_with(|| value.to_vec());
+        }
+        WriteOperation::CompareAndSet { key, expected, new } => {
+            if kv_state.get(key) == expected.as_ref() {
+                match new {
+                    Some(new_value) => {
+                        kv_state.insert(key.to_vec(), new_value.to_vec());
+                    }
+                    None => {
+                        kv_state.remove(key);
+                    }
                 }
             }
         }
     }
 }
 
+fn update_via_batch(kv_state: &mut BTreeMap<Vec<u8>, Vec<u8>>, batch: &Batch) {
+    for operation in &batch.operations {
+        apply_operation(kv_state, operation);
+    }
+}
+
 fn get_n_operation(line: String) -> usize {
     let parts : Vec<String> = line.split('=').map(|x| x.to_string()).collect();
     if parts.len() != 2 {
@@ -285,6 +871,12 @@ fn get_first_entry(operation: &WriteOperation) -> u8 {
         WriteOperation::DeletePrefix { key_prefix } => {
             key_prefix[0]
         }
+        WriteOperation::PutIfAbsent { key, value: _ } => {
+            key[0]
+        }
+        WriteOperation::CompareAndSet { key, expected: _, new: _ } => {
+            key[0]
+        }
     }
 }
 
@@ -294,9 +886,193 @@ fn update_firsts(first_bytes: &mut BTreeSet<u8>, batch: &Batch) {
     }
 }
 
+/// Bundles the Scylla connection and the in-memory oracle state that both
+/// the log-replay path and the `--quickcheck` fuzzing path thread through
+/// `apply_and_check_batch`, so that function doesn't need a separate
+/// parameter for each of them.
+struct BatchHarness<'a> {
+    session: &'a Session,
+    prepared: &'a PreparedStatements,
+    kv_state: BTreeMap<Vec<u8>, Vec<u8>>,
+    first_bytes: BTreeSet<u8>,
+}
+
+impl<'a> BatchHarness<'a> {
+    fn new(session: &'a Session, prepared: &'a PreparedStatements) -> Self {
+        BatchHarness { session, prepared, kv_state: BTreeMap::new(), first_bytes: BTreeSet::new() }
+    }
+}
+
+/// Applies `batch` to both the in-memory oracle and Scylla, then asserts
+/// that a full prefix-scan reconciliation over every first byte seen so far
+/// agrees with the oracle. Shared by the log-replay path and the
+/// `--quickcheck` fuzzing path so both exercise the exact same consistency
+/// check.
+async fn apply_and_check_batch(
+    harness: &mut BatchHarness<'_>,
+    prior_batches: &[Batch],
+    batch: &Batch,
+    pos: usize,
+    n_batches: usize,
+    max_chunk_bytes: usize,
+) {
+    update_firsts(&mut harness.first_bytes, batch);
+    update_via_batch(&mut harness.kv_state, batch);
+    let applied = write_batch_internal_with(
+        harness.session,
+        harness.prepared,
+        batch.operations.clone(),
+        WriteBatchType::Logged,
+        DEFAULT_MAX_BATCH_BYTES,
+        max_chunk_bytes,
+    )
+    .await;
+    println!("applied={:?}", applied);
+    let mut kv_state_read = BTreeMap::new();
+    for first_byte in harness.first_bytes.iter() {
+        let key_prefix = vec![*first_byte];
+        let mut key_values = Box::pin(scan_prefix(harness.session, key_prefix).await);
+        while let Some((key, value)) = key_values.next().await {
+            let mut big_key = vec![*first_byte];
+            big_key.extend(key);
+            kv_state_read.insert(big_key, value);
+        }
+    }
+    if kv_state_read != harness.kv_state {
+        println!("              ---------------------");
+        println!("Inconsistency at pos={} n_batches={}", pos, n_batches);
+        println!("failing batch sequence (replay these, in order, as a regression case):");
+        for (i, prior_batch) in prior_batches.iter().enumerate() {
+            println!("-- sequence batch {} --", i);
+            print_batch(prior_batch);
+        }
+        println!("-- sequence batch {} (failing) --", prior_batches.len());
+        print_batch(batch);
+        detect_collision(batch);
+        panic!("Incoherence between the database and the current state");
+    }
+}
+
+/// Small key space so that `Put`, `Delete`, and `DeletePrefix` operations
+/// generated by `quickcheck` frequently collide on the same keys/prefixes.
+const QUICKCHECK_KEY_SPACE: u8 = 8;
+const QUICKCHECK_MAX_OPERATIONS: usize = 8;
+const DEFAULT_QUICKCHECK_BATCHES: usize = 100;
+
+/// Values up to this many bytes are generated for `Put`, so they routinely
+/// exceed `QUICKCHECK_MAX_CHUNK_BYTES` and exercise chunk0-4's chunking and
+/// reassembly, not just single-cell writes.
+const QUICKCHECK_MAX_VALUE_BYTES: usize = 12;
+
+/// `max_chunk_bytes` used by the quickcheck path, kept small (rather than
+/// `DEFAULT_MAX_CHUNK_BYTES`) so the generated values actually get split
+/// across multiple clustering rows instead of always fitting in one.
+const QUICKCHECK_MAX_CHUNK_BYTES: usize = 3;
+
+/// A key is usually exactly `BUCKET_PREFIX_LEN` byte (pinning one bucket),
+/// but occasionally two, so the bucket fan-out and same-bucket clustering
+/// paths chunk0-7 added both get exercised.
+fn arbitrary_key(g: &mut Gen) -> Vec<u8> {
+    let n_key_bytes = if u8::arbitrary(g) % 4 == 0 { 2 } else { 1 };
+    (0..n_key_bytes).map(|_| u8::arbitrary(g) % QUICKCHECK_KEY_SPACE).collect()
+}
+
+fn arbitrary_value(g: &mut Gen) -> Vec<u8> {
+    let n_value_bytes = 1 + (usize::arbitrary(g) % QUICKCHECK_MAX_VALUE_BYTES);
+    (0..n_value_bytes).map(|_| u8::arbitrary(g)).collect()
+}
+
+impl Arbitrary for WriteOperation {
+    fn arbitrary(g: &mut Gen) -> WriteOperation {
+        let key = arbitrary_key(g);
+        match u8::arbitrary(g) % 5 {
+            0 => WriteOperation::Put { key, value: arbitrary_value(g) },
+            1 => WriteOperation::Delete { key },
+            2 => WriteOperation::DeletePrefix { key_prefix: key },
+            3 => WriteOperation::PutIfAbsent { key, value: arbitrary_value(g) },
+            _ => {
+                let expected = bool::arbitrary(g).then(|| arbitrary_value(g));
+                let new = bool::arbitrary(g).then(|| arbitrary_value(g));
+                WriteOperation::CompareAndSet { key, expected, new }
+            }
+        }
+    }
+}
+
+impl Arbitrary for Batch {
+    fn arbitrary(g: &mut Gen) -> Batch {
+        let n_operation = 1 + (usize::arbitrary(g) % QUICKCHECK_MAX_OPERATIONS);
+        let operations = (0..n_operation).map(|_| WriteOperation::arbitrary(g)).collect();
+        Batch { operations }
+    }
+}
+
+/// Generates a `CompareAndSet`/`PutIfAbsent`-aware operation against `state`
+/// (a running copy of the oracle as of this point in the batch), biasing
+/// `expected` toward the key's real current value about half the time so
+/// the match path is actually exercised and not just the near-certain
+/// mismatch a purely random value would hit.
+fn arbitrary_operation(g: &mut Gen, state: &BTreeMap<Vec<u8>, Vec<u8>>) -> WriteOperation {
+    let key = arbitrary_key(g);
+    match u8::arbitrary(g) % 5 {
+        0 => WriteOperation::Put { key, value: arbitrary_value(g) },
+        1 => WriteOperation::Delete { key },
+        2 => WriteOperation::DeletePrefix { key_prefix: key },
+        3 => WriteOperation::PutIfAbsent { key, value: arbitrary_value(g) },
+        _ => {
+            let real_current = state.get(&key).cloned();
+            let expected = if bool::arbitrary(g) {
+                real_current
+            } else {
+                bool::arbitrary(g).then(|| arbitrary_value(g))
+            };
+            let new = bool::arbitrary(g).then(|| arbitrary_value(g));
+            WriteOperation::CompareAndSet { key, expected, new }
+        }
+    }
+}
+
+/// Generates a batch the same way `Batch::arbitrary` does, except
+/// `CompareAndSet` operations are generated against a running copy of
+/// `kv_state` (updated after each operation, so later ops in the batch see
+/// the effect of earlier ones) rather than in a vacuum, so the consistency
+/// check actually exercises both the match and mismatch paths instead of
+/// almost always hitting mismatch by chance.
+fn arbitrary_batch(g: &mut Gen, kv_state: &BTreeMap<Vec<u8>, Vec<u8>>) -> Batch {
+    let n_operation = 1 + (usize::arbitrary(g) % QUICKCHECK_MAX_OPERATIONS);
+    let mut state = kv_state.clone();
+    let mut operations = Vec::with_capacity(n_operation);
+    for _ in 0..n_operation {
+        let operation = arbitrary_operation(g, &state);
+        apply_operation(&mut state, &operation);
+        operations.push(operation);
+    }
+    Batch { operations }
+}
+
+/// Generates `n_batches` random batches with `quickcheck::Arbitrary` and
+/// checks each one against the `BTreeMap` oracle the same way the
+/// log-replay path does. On a mismatch, the whole sequence of batches
+/// applied so far is printed so it can be replayed as a regression case.
+async fn run_quickcheck(session: &Session, prepared: &PreparedStatements, n_batches: usize) {
+    let mut gen = Gen::new(QUICKCHECK_KEY_SPACE as usize);
+    let mut harness = BatchHarness::new(session, prepared);
+    let mut history = Vec::new();
+    for pos in 0..n_batches {
+        let batch = arbitrary_batch(&mut gen, &harness.kv_state);
+        apply_and_check_batch(&mut harness, &history, &batch, pos, n_batches, QUICKCHECK_MAX_CHUNK_BYTES).await;
+        history.push(batch);
+    }
+    println!(
+        "quickcheck fuzzing completed with n_batches={} and no inconsistency found",
+        n_batches
+    );
+}
+
 #[tokio::main]
 async fn main() {
     let session = create_test_session().await;
+    let prepared = PreparedStatements::new(&session).await;
     //
     let mut arguments = Vec::new();
     for argument in std::env::args() {
@@ -305,8 +1081,18 @@ async fn main() {
     println!("arguments={:?}", arguments);
     let n_arg = arguments.len();
     println!("n_arg={}", n_arg);
+    if n_arg >= 2 && arguments[1] == "--quickcheck" {
+        let n_batches = if n_arg >= 3 {
+            arguments[2].parse::<usize>().expect("a batch count")
+        } else {
+            DEFAULT_QUICKCHECK_BATCHES
+        };
+        run_quickcheck(&session, &prepared, n_batches).await;
+        return;
+    }
     if n_arg != 2 {
         println!("test_scylla_db_batch_sequence [FileI]");
+        println!("test_scylla_db_batch_sequence --quickcheck [NBatches]");
         std::process::exit(1)
     }
     let file = arguments[1].clone();
@@ -344,30 +1130,10 @@ async fn main() {
     //
     // Now looping over the batches
     //
-    let mut kv_state = BTreeMap::new();
-    let mut first_bytes = BTreeSet::new();
-    let mut pos = 0;
-    for batch in batches {
-        update_firsts(&mut first_bytes, &batch);
-        update_via_batch(&mut kv_state, &batch);
-        write_batch_internal(&session, batch.operations.clone()).await;
-        let mut kv_state_read = BTreeMap::new();
-        for first_byte in &first_bytes {
-            let key_prefix = vec![first_byte.clone()];
-            let key_values = find_key_values_by_prefix(&session, key_prefix).await;
-            for (key,value) in key_values {
-                let mut big_key = vec![first_byte.clone()];
-                big_key.extend(key);
-                kv_state_read.insert(big_key, value);
-            }
-        }
-        if kv_state_read != kv_state {
-            println!("              ---------------------");
-            println!("Inconsistency at pos={} n_batches={}", pos, n_batches);
-            print_batch(&batch);
-            detect_collision(&batch);
-            panic!("Incoherence between the database and the current state");
-        }
-        pos += 1;
+    let mut harness = BatchHarness::new(&session, &prepared);
+    let mut history = Vec::new();
+    for (pos, batch) in batches.into_iter().enumerate() {
+        apply_and_check_batch(&mut harness, &history, &batch, pos, n_batches, DEFAULT_MAX_CHUNK_BYTES).await;
+        history.push(batch);
     }
 }